@@ -1,54 +1,139 @@
 //! PredExchange Rust core - orderbook engine exposed to Python via PyO3.
 
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// One OHLC bar covering `[bucket_start, bucket_start + resolution)` in ms.
+struct Candle {
+    bucket_start: u64,
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+}
+
+/// A match produced by `add_order` crossing a resting order.
+#[pyclass(get_all)]
+#[derive(Clone)]
+struct Fill {
+    maker_id: u64,
+    taker_id: u64,
+    price: f64,
+    size: f64,
+}
 
 /// In-memory L2 orderbook: bids and asks as sorted maps (price -> size).
 #[pyclass]
 struct OrderbookEngine {
     market_id: String,
     asset_id: String,
-    bids: BTreeMap<u64, f64>, // price in basis points (0-10000) -> size
+    bids: BTreeMap<u64, f64>, // price as an integer multiple of tick_size -> size
     asks: BTreeMap<u64, f64>,
     has_snapshot: bool,
+    candle_resolution_ms: Option<u64>,
+    candles: Vec<Candle>,
+    last_seq: Option<u64>,
+    needs_resync: bool,
+    // Resting orders for the FIFO price-time matching mode, kept separate
+    // from the `bids`/`asks` L2 mirror above.
+    bid_orders: BTreeMap<u64, VecDeque<(u64, f64)>>,
+    ask_orders: BTreeMap<u64, VecDeque<(u64, f64)>>,
+    order_index: HashMap<u64, (bool, u64)>, // order_id -> (is_bid, price_key)
+    tick_size: f64,
 }
 
+/// Tolerance, in ticks, for treating a price as landing on a tick boundary.
+const TICK_EPSILON: f64 = 1e-6;
+
 #[pymethods]
 impl OrderbookEngine {
     #[new]
-    fn new(market_id: String, asset_id: String) -> Self {
+    fn new(market_id: String, asset_id: String, tick_size: f64) -> Self {
         OrderbookEngine {
             market_id,
             asset_id,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             has_snapshot: false,
+            candle_resolution_ms: None,
+            candles: Vec::new(),
+            last_seq: None,
+            needs_resync: false,
+            bid_orders: BTreeMap::new(),
+            ask_orders: BTreeMap::new(),
+            order_index: HashMap::new(),
+            tick_size,
         }
     }
 
-    /// Apply a full snapshot: bids/asks as list of (price, size).
-    fn apply_snapshot(&mut self, bids: Vec<(f64, f64)>, asks: Vec<(f64, f64)>) {
+    /// Apply a full snapshot: bids/asks as list of (price, size), with the
+    /// sequence number this snapshot establishes as a baseline for deltas.
+    /// Returns the number of levels rejected for a price outside `[0, 1]`,
+    /// a price not landing on a tick, or a negative size.
+    fn apply_snapshot(
+        &mut self,
+        bids: Vec<(f64, f64)>,
+        asks: Vec<(f64, f64)>,
+        timestamp_ms: u64,
+        seq: u64,
+    ) -> u32 {
         self.bids.clear();
         self.asks.clear();
+        let mut rejected = 0;
         for (p, s) in bids {
-            if s >= 0.0 && p >= 0.0 && p <= 1.0 {
-                self.bids.insert(price_to_key(p), s);
+            match self.price_to_key(p) {
+                Some(key) if s >= 0.0 => {
+                    self.bids.insert(key, s);
+                }
+                _ => rejected += 1,
             }
         }
         for (p, s) in asks {
-            if s >= 0.0 && p >= 0.0 && p <= 1.0 {
-                self.asks.insert(price_to_key(p), s);
+            match self.price_to_key(p) {
+                Some(key) if s >= 0.0 => {
+                    self.asks.insert(key, s);
+                }
+                _ => rejected += 1,
             }
         }
         self.has_snapshot = true;
+        self.last_seq = Some(seq);
+        self.needs_resync = false;
+        self.observe(timestamp_ms);
+        rejected
     }
 
-    /// Apply delta: side "BUY" or "SELL", price, size. Size 0 removes level.
-    fn apply_delta(&mut self, side: &str, price: f64, size: f64) {
+    /// Apply delta: side "BUY" or "SELL", price, size, sequence number. Size 0
+    /// removes level. A delta whose `seq` isn't exactly `last_seq + 1` (stale,
+    /// duplicate, or a gap) is rejected and flips `needs_resync` instead of
+    /// mutating the book. Returns `false` (without mutating the book) when the
+    /// price is outside `[0, 1]` or doesn't land on a tick boundary.
+    fn apply_delta(
+        &mut self,
+        side: &str,
+        price: f64,
+        size: f64,
+        timestamp_ms: u64,
+        seq: u64,
+    ) -> bool {
         if !self.has_snapshot {
-            return;
+            return false;
+        }
+        if let Some(last) = self.last_seq {
+            if seq != last + 1 {
+                self.needs_resync = true;
+                return false;
+            }
         }
-        let key = price_to_key(price);
+        // The delta is in-order regardless of whether its price lands on a
+        // tick, so advance `last_seq` here - otherwise an off-tick delta
+        // would leave `last_seq` behind and the next (perfectly valid) delta
+        // would spuriously look like a gap.
+        self.last_seq = Some(seq);
+        let Some(key) = self.price_to_key(price) else {
+            return false;
+        };
         let map = if side.eq_ignore_ascii_case("BUY") {
             &mut self.bids
         } else {
@@ -59,16 +144,41 @@ impl OrderbookEngine {
         } else {
             map.insert(key, size);
         }
+        self.observe(timestamp_ms);
+        true
+    }
+
+    /// Whether an out-of-sequence delta (stale, duplicate, or a gap) was
+    /// rejected since the last snapshot; the caller should request a fresh
+    /// snapshot to resync.
+    #[getter]
+    fn needs_resync(&self) -> bool {
+        self.needs_resync
+    }
+
+    /// Start (or restart) OHLC aggregation with a bucket width of `resolution_secs`.
+    fn configure(&mut self, resolution_secs: u64) {
+        self.candle_resolution_ms = Some(resolution_secs * 1000);
+        self.candles.clear();
+    }
+
+    /// Closed and in-progress candles whose bucket falls within `[from_ms, to_ms]`.
+    fn candles(&self, from_ms: u64, to_ms: u64) -> Vec<(u64, f64, f64, f64, f64)> {
+        self.candles
+            .iter()
+            .filter(|c| c.bucket_start >= from_ms && c.bucket_start <= to_ms)
+            .map(|c| (c.bucket_start, c.open, c.high, c.low, c.close))
+            .collect()
     }
 
     #[getter]
     fn best_bid(&self) -> Option<f64> {
-        self.bids.iter().next_back().map(|(k, _)| key_to_price(*k))
+        self.bids.iter().next_back().map(|(k, _)| self.key_to_price(*k))
     }
 
     #[getter]
     fn best_ask(&self) -> Option<f64> {
-        self.asks.iter().next().map(|(k, _)| key_to_price(*k))
+        self.asks.iter().next().map(|(k, _)| self.key_to_price(*k))
     }
 
     #[getter]
@@ -85,19 +195,295 @@ impl OrderbookEngine {
     fn has_snapshot(&self) -> bool {
         self.has_snapshot
     }
-}
 
-fn price_to_key(p: f64) -> u64 {
-    (p.clamp(0.0, 1.0) * 1_000_000.0).round() as u64
+    /// Size-weighted mid: leans toward the side with less resting size,
+    /// i.e. the side more likely to be swept next. A better fair-value
+    /// estimate than `mid_price` for thin, imbalanced books.
+    #[getter]
+    fn microprice(&self) -> Option<f64> {
+        let (&bid_key, &bid_size) = self.bids.iter().next_back()?;
+        let (&ask_key, &ask_size) = self.asks.iter().next()?;
+        if bid_size + ask_size <= 0.0 {
+            return None;
+        }
+        let best_bid = self.key_to_price(bid_key);
+        let best_ask = self.key_to_price(ask_key);
+        Some((best_bid * ask_size + best_ask * bid_size) / (bid_size + ask_size))
+    }
+
+    /// Touch size imbalance in `[-1, 1]`: positive means more resting bid size.
+    #[getter]
+    fn imbalance(&self) -> Option<f64> {
+        let (_, &bid_size) = self.bids.iter().next_back()?;
+        let (_, &ask_size) = self.asks.iter().next()?;
+        if bid_size + ask_size <= 0.0 {
+            return None;
+        }
+        Some((bid_size - ask_size) / (bid_size + ask_size))
+    }
+
+    /// Simulate walking the book with a marketable order of `size`.
+    ///
+    /// Returns `(avg_fill_price, filled_size, slippage_bps)` where `filled_size`
+    /// may be less than `size` if the book can't fill it. `slippage_bps` is
+    /// measured against the touch price on the side being consumed.
+    fn quote(&self, side: &str, size: f64) -> Option<(f64, f64, f64)> {
+        if !self.has_snapshot {
+            return None;
+        }
+        let buy = side.eq_ignore_ascii_case("BUY");
+        let mut remaining = size;
+        let mut notional = 0.0;
+        let mut filled = 0.0;
+        let best_price;
+        if buy {
+            best_price = self.best_ask()?;
+            for (&key, &level_size) in self.asks.iter() {
+                if remaining <= 0.0 {
+                    break;
+                }
+                let price = self.key_to_price(key);
+                let fill = level_size.min(remaining);
+                notional += price * fill;
+                filled += fill;
+                remaining -= fill;
+            }
+        } else {
+            best_price = self.best_bid()?;
+            for (&key, &level_size) in self.bids.iter().rev() {
+                if remaining <= 0.0 {
+                    break;
+                }
+                let price = self.key_to_price(key);
+                let fill = level_size.min(remaining);
+                notional += price * fill;
+                filled += fill;
+                remaining -= fill;
+            }
+        }
+        if filled <= 0.0 {
+            return None;
+        }
+        let avg_fill_price = notional / filled;
+        let slippage_bps = (avg_fill_price - best_price) / best_price * 10_000.0;
+        Some((avg_fill_price, filled, slippage_bps))
+    }
+
+    /// Top-`levels` bid and ask ladders as `(price, size_at_level, cumulative_size)`,
+    /// bids descending from the touch and asks ascending from the touch.
+    fn depth(&self, levels: usize) -> (Vec<(f64, f64, f64)>, Vec<(f64, f64, f64)>) {
+        let mut cumulative = 0.0;
+        let bid_levels = self
+            .bids
+            .iter()
+            .rev()
+            .take(levels)
+            .map(|(&key, &size)| {
+                cumulative += size;
+                (self.key_to_price(key), size, cumulative)
+            })
+            .collect();
+        cumulative = 0.0;
+        let ask_levels = self
+            .asks
+            .iter()
+            .take(levels)
+            .map(|(&key, &size)| {
+                cumulative += size;
+                (self.key_to_price(key), size, cumulative)
+            })
+            .collect();
+        (bid_levels, ask_levels)
+    }
+
+    /// Submit an order to the FIFO price-time matching core: crosses against
+    /// resting orders on the opposite side from the touch inward, filling
+    /// first-in-first-out within each price level, and rests any remainder.
+    fn add_order(&mut self, order_id: u64, side: &str, price: f64, size: f64) -> PyResult<Vec<Fill>> {
+        let is_bid = side.eq_ignore_ascii_case("BUY");
+        let Some(key) = self.price_to_key(price) else {
+            return Err(PyValueError::new_err(
+                "price is outside [0, 1] or does not land on a tick boundary",
+            ));
+        };
+        let mut remaining = size;
+        let mut fills = Vec::new();
+        let tick_size = self.tick_size;
+
+        let opposite = if is_bid {
+            &mut self.ask_orders
+        } else {
+            &mut self.bid_orders
+        };
+        loop {
+            if remaining <= 0.0 {
+                break;
+            }
+            let best_key = if is_bid {
+                opposite.keys().next().copied()
+            } else {
+                opposite.keys().next_back().copied()
+            };
+            let Some(level_key) = best_key else { break };
+            let crosses = if is_bid { level_key <= key } else { level_key >= key };
+            if !crosses {
+                break;
+            }
+            let queue = opposite.get_mut(&level_key).unwrap();
+            while remaining > 0.0 {
+                let Some((maker_id, resting_size)) = queue.front_mut() else {
+                    break;
+                };
+                let fill = resting_size.min(remaining);
+                fills.push(Fill {
+                    maker_id: *maker_id,
+                    taker_id: order_id,
+                    price: level_key as f64 * tick_size,
+                    size: fill,
+                });
+                remaining -= fill;
+                *resting_size -= fill;
+                if *resting_size <= 0.0 {
+                    let (filled_id, _) = queue.pop_front().unwrap();
+                    self.order_index.remove(&filled_id);
+                } else {
+                    break;
+                }
+            }
+            if queue.is_empty() {
+                opposite.remove(&level_key);
+            }
+        }
+
+        if remaining > 0.0 {
+            let own = if is_bid {
+                &mut self.bid_orders
+            } else {
+                &mut self.ask_orders
+            };
+            own.entry(key).or_default().push_back((order_id, remaining));
+            self.order_index.insert(order_id, (is_bid, key));
+        }
+        Ok(fills)
+    }
+
+    /// Remove a resting order from the matching book. Returns `true` if an
+    /// order with that id was found and removed.
+    fn cancel_order(&mut self, order_id: u64) -> bool {
+        let Some((is_bid, key)) = self.order_index.remove(&order_id) else {
+            return false;
+        };
+        let book = if is_bid {
+            &mut self.bid_orders
+        } else {
+            &mut self.ask_orders
+        };
+        if let Some(queue) = book.get_mut(&key) {
+            queue.retain(|(id, _)| *id != order_id);
+            if queue.is_empty() {
+                book.remove(&key);
+            }
+        }
+        true
+    }
+
+    /// Shrink a resting order's size in place, keeping its queue position.
+    /// `new_size` must be positive and strictly less than the order's current size.
+    fn reduce_order(&mut self, order_id: u64, new_size: f64) -> PyResult<()> {
+        let Some(&(is_bid, key)) = self.order_index.get(&order_id) else {
+            return Err(PyValueError::new_err("unknown order_id"));
+        };
+        let book = if is_bid {
+            &mut self.bid_orders
+        } else {
+            &mut self.ask_orders
+        };
+        let queue = book.get_mut(&key).ok_or_else(|| PyValueError::new_err("unknown order_id"))?;
+        let entry = queue
+            .iter_mut()
+            .find(|(id, _)| *id == order_id)
+            .ok_or_else(|| PyValueError::new_err("unknown order_id"))?;
+        if new_size <= 0.0 || new_size >= entry.1 {
+            return Err(PyValueError::new_err(
+                "new_size must be positive and strictly less than the order's current size",
+            ));
+        }
+        entry.1 = new_size;
+        Ok(())
+    }
 }
 
-fn key_to_price(k: u64) -> f64 {
-    k as f64 / 1_000_000.0
+impl OrderbookEngine {
+    /// Record the current mid-price into the candle series, opening new
+    /// buckets (and back-filling any skipped ones with flat candles) as needed.
+    fn observe(&mut self, timestamp_ms: u64) {
+        let Some(resolution_ms) = self.candle_resolution_ms else {
+            return;
+        };
+        let Some(mid) = self.mid_price() else {
+            return;
+        };
+        let bucket_start = (timestamp_ms / resolution_ms) * resolution_ms;
+        match self.candles.last_mut() {
+            Some(last) if last.bucket_start == bucket_start => {
+                last.high = last.high.max(mid);
+                last.low = last.low.min(mid);
+                last.close = mid;
+            }
+            Some(last) => {
+                let mut next_start = last.bucket_start + resolution_ms;
+                let prior_close = last.close;
+                while next_start < bucket_start {
+                    self.candles.push(Candle {
+                        bucket_start: next_start,
+                        open: prior_close,
+                        high: prior_close,
+                        low: prior_close,
+                        close: prior_close,
+                    });
+                    next_start += resolution_ms;
+                }
+                self.candles.push(Candle {
+                    bucket_start,
+                    open: prior_close,
+                    high: mid.max(prior_close),
+                    low: mid.min(prior_close),
+                    close: mid,
+                });
+            }
+            None => self.candles.push(Candle {
+                bucket_start,
+                open: mid,
+                high: mid,
+                low: mid,
+                close: mid,
+            }),
+        }
+    }
+
+    /// Integer tick-mantissa for `p`, or `None` if `p` falls outside `[0, 1]`
+    /// or isn't within `TICK_EPSILON` ticks of a tick boundary.
+    fn price_to_key(&self, p: f64) -> Option<u64> {
+        if !(0.0..=1.0).contains(&p) {
+            return None;
+        }
+        let mantissa = p / self.tick_size;
+        let rounded = mantissa.round();
+        if (mantissa - rounded).abs() > TICK_EPSILON {
+            return None;
+        }
+        Some(rounded as u64)
+    }
+
+    fn key_to_price(&self, k: u64) -> f64 {
+        k as f64 * self.tick_size
+    }
 }
 
 /// Python module entry point.
 #[pymodule]
 fn predexchange_core(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<OrderbookEngine>()?;
+    m.add_class::<Fill>()?;
     Ok(())
 }